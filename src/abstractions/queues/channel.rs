@@ -1,35 +1,88 @@
 use std::any::Any;
+use std::collections::VecDeque;
 use std::error::Error;
 use std::fmt;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 use abstractions::futures::future::Future;
 use abstractions::poll::{Poll, Async};
-use abstractions::queues::slot::{Slot, Token};
+use abstractions::sinks::sink::{Sink, StartSend, AsyncSink};
 use abstractions::streams::stream::Stream;
-use abstractions::tasks::task;
+use abstractions::tasks::task::{self, Task};
 
 pub fn create<T, E>() -> (Sender<T, E>, Receiver<T, E>) {
+    create_bounded(1)
+}
+
+/// Creates an mpsc channel whose `Sender` half can be cloned, allowing many
+/// producers to feed a single `Receiver`.
+///
+/// `Message::Done` is only produced into the queue once every clone of the
+/// `Sender` has been dropped.
+pub fn create_mpsc<T, E>() -> (Sender<T, E>, Receiver<T, E>) {
+    create()
+}
+
+/// Creates a channel buffering up to `buffer` unconsumed messages.
+///
+/// Unlike `create`, which only ever lets a producer have a single in-flight
+/// value, this allows up to `buffer` values to be queued before a sender
+/// blocks waiting for the receiver to catch up. Blocked senders are woken in
+/// the order they parked.
+///
+/// # Panics
+///
+/// Panics if `buffer` is `0`. A zero-capacity queue would make
+/// `state.queue.len() < state.capacity` never hold, so every send would park
+/// forever regardless of how much the receiver polls; this isn't rendezvous
+/// behavior, just a permanent deadlock, so it's rejected up front instead.
+pub fn create_bounded<T, E>(buffer: usize) -> (Sender<T, E>, Receiver<T, E>) {
+    assert!(buffer > 0, "create_bounded requires a buffer capacity of at least 1");
     let inner = Arc::new(Inner {
-        slot: Slot::new(None),
+        state: Mutex::new(State {
+            queue: VecDeque::new(),
+            capacity: buffer,
+            closed: false,
+            receiver_task: None,
+            blocked_senders: VecDeque::new(),
+            next_parked_id: 0,
+        }),
         receiver_gone: AtomicBool::new(false),
+        sender_count: AtomicUsize::new(1),
     });
     let sender = Sender {
         inner: inner.clone(),
+        parked_id: None,
     };
     let receiver = Receiver {
         inner: inner,
-        on_full_token: None,
     };
     (sender, receiver)
 }
 
+/// Creates a channel that never applies backpressure: producers can always
+/// push another value, matching the unbounded channels in actix-utils and
+/// local-channel.
+///
+/// `UnboundedSender::send` is synchronous because the underlying queue has
+/// no capacity limit; it only ever fails once the receiver has gone away or
+/// `Receiver::close` has been called.
+pub fn create_unbounded<T, E>() -> (UnboundedSender<T, E>, Receiver<T, E>) {
+    let (sender, receiver) = create_bounded(usize::max_value());
+    (UnboundedSender { inner: sender }, receiver)
+}
+
 /// The transmission end of a channel which is used to send values.
 ///
 /// This is created by the `channel` method in the `stream` module.
 pub struct Sender<T, E> {
     inner: Arc<Inner<T, E>>,
+    // Set while this `Sender` is parked in `blocked_senders` via
+    // `Sink::start_send`, so the registration can be retracted if this sender
+    // is dropped or re-polled before being woken, instead of leaking a dead
+    // entry ahead of real blocked senders.
+    parked_id: Option<u64>,
 }
 
 /// A future returned by the `Sender::send` method which will resolve to the
@@ -38,7 +91,10 @@ pub struct Sender<T, E> {
 pub struct FutureSender<T, E> {
     sender: Option<Sender<T, E>>,
     data: Option<Result<T, E>>,
-    on_empty_token: Option<Token>,
+    // Set while this future is parked in `blocked_senders`, so the
+    // registration can be retracted if the future is dropped before being
+    // woken instead of leaking a dead entry ahead of real blocked senders.
+    parked_id: Option<u64>,
 }
 
 /// The receiving end of a channel which implements the `Stream` trait.
@@ -49,12 +105,43 @@ pub struct FutureSender<T, E> {
 #[must_use = "streams do nothing unless polled"]
 pub struct Receiver<T, E> {
     inner: Arc<Inner<T, E>>,
-    on_full_token: Option<Token>,
 }
 
 struct Inner<T, E> {
-    slot: Slot<Message<Result<T, E>>>,
+    state: Mutex<State<T, E>>,
     receiver_gone: AtomicBool,
+    sender_count: AtomicUsize,
+}
+
+struct State<T, E> {
+    queue: VecDeque<Message<Result<T, E>>>,
+    capacity: usize,
+    // Guarded by the same lock as `blocked_senders` so that `Receiver::close`
+    // setting this and draining `blocked_senders` is atomic with respect to a
+    // sender checking `closed` before deciding to park: either a sender sees
+    // `closed` and bails out, or it parks before the drain and gets woken by it.
+    closed: bool,
+    receiver_task: Option<Task>,
+    blocked_senders: VecDeque<(u64, Task)>,
+    next_parked_id: u64,
+}
+
+impl<T, E> State<T, E> {
+    /// Parks the current task as a blocked sender and returns an id that can
+    /// later be passed to `unregister_blocked_sender` to retract it, e.g. if
+    /// the parked future is dropped before being woken.
+    fn park_current_sender(&mut self) -> u64 {
+        let id = self.next_parked_id;
+        self.next_parked_id += 1;
+        self.blocked_senders.push_back((id, task::park()));
+        id
+    }
+
+    /// Removes a previously parked sender registration, if it's still present.
+    /// A no-op if it was already popped and woken by `unpark_one_sender`.
+    fn unregister_blocked_sender(&mut self, id: u64) {
+        self.blocked_senders.retain(|&(parked_id, _)| parked_id != id);
+    }
 }
 
 enum Message<T> {
@@ -87,40 +174,138 @@ impl<T, E> Error for SendError<T, E>
     }
 }
 
+/// Error type returned by `Sender::try_send`.
+///
+/// Unlike `SendError`, this distinguishes a channel that's merely full right
+/// now (`Full`) from one that can never accept another value (`Disconnected`,
+/// because the receiver is gone or `Receiver::close` was called).
+pub enum TrySendError<T, E> {
+    Full(Result<T, E>),
+    Disconnected(Result<T, E>),
+}
+
+impl<T, E> TrySendError<T, E> {
+    /// Returns whether this error is because the channel is currently full.
+    pub fn is_full(&self) -> bool {
+        match *self {
+            TrySendError::Full(..) => true,
+            TrySendError::Disconnected(..) => false,
+        }
+    }
+
+    /// Returns whether this error is because the channel can never accept
+    /// another value.
+    pub fn is_disconnected(&self) -> bool {
+        match *self {
+            TrySendError::Full(..) => false,
+            TrySendError::Disconnected(..) => true,
+        }
+    }
+
+    /// Consumes this error, returning the unsent value.
+    pub fn into_inner(self) -> Result<T, E> {
+        match self {
+            TrySendError::Full(v) => v,
+            TrySendError::Disconnected(v) => v,
+        }
+    }
+}
+
+impl<T, E> fmt::Debug for TrySendError<T, E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_tuple(if self.is_full() { "Full" } else { "Disconnected" })
+            .field(&"...")
+            .finish()
+    }
+}
+
+impl<T, E> fmt::Display for TrySendError<T, E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_full() {
+            write!(fmt, "send failed because channel is full")
+        } else {
+            write!(fmt, "send failed because receiver is gone or channel is closed")
+        }
+    }
+}
+
+impl<T, E> Error for TrySendError<T, E>
+    where T: Any, E: Any
+{
+    fn description(&self) -> &str {
+        if self.is_full() {
+            "send failed because channel is full"
+        } else {
+            "send failed because receiver is gone or channel is closed"
+        }
+    }
+}
+
 
 impl<T, E> Stream for Receiver<T, E> {
     type Item = T;
     type Error = E;
 
     fn poll(&mut self) -> Poll<Option<T>, E> {
-        if let Some(token) = self.on_full_token.take() {
-            self.inner.slot.cancel(token);
-        }
-
-        match self.inner.slot.try_consume() {
-            Ok(Message::Data(Ok(e))) => Ok(Async::Ready(Some(e))),
-            Ok(Message::Data(Err(e))) => Err(e),
-            Ok(Message::Done) => Ok(Async::Ready(None)),
-            Err(..) => {
-                let task = task::park();
-                self.on_full_token = Some(self.inner.slot.on_full(move |_| {
-                    task.unpark();
-                }));
-                Ok(Async::NotReady)
+        let mut state = self.inner.state.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(Message::Data(Ok(e))) => {
+                unpark_one_sender(&mut state);
+                Ok(Async::Ready(Some(e)))
+            }
+            Some(Message::Data(Err(e))) => {
+                unpark_one_sender(&mut state);
+                Err(e)
+            }
+            Some(Message::Done) => Ok(Async::Ready(None)),
+            None => {
+                if state.closed {
+                    Ok(Async::Ready(None))
+                } else {
+                    state.receiver_task = Some(task::park());
+                    Ok(Async::NotReady)
+                }
             }
         }
     }
 }
 
+fn unpark_one_sender<T, E>(state: &mut State<T, E>) {
+    if let Some((_, task)) = state.blocked_senders.pop_front() {
+        task.unpark();
+    }
+}
+
+impl<T, E> Receiver<T, E> {
+    /// Closes the channel for further sends while letting the consumer drain
+    /// whatever is already buffered.
+    ///
+    /// After calling this, every `FutureSender`/`Sink::start_send` fails with
+    /// `SendError`, but `poll` keeps yielding already-queued messages until
+    /// the backlog is exhausted, at which point it settles on
+    /// `Ok(Async::Ready(None))` instead of parking forever.
+    ///
+    /// The flag is set and `blocked_senders` drained under the same lock a
+    /// sender holds while checking `closed` and deciding whether to park, so
+    /// a sender can never park after this, the one and only drain, has
+    /// already run.
+    pub fn close(&mut self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.closed = true;
+        for (_, task) in state.blocked_senders.drain(..) {
+            task.unpark();
+        }
+    }
+}
+
 impl<T, E> Drop for Receiver<T, E> {
     fn drop(&mut self) {
         self.inner.receiver_gone.store(true, Ordering::SeqCst);
-        if let Some(token) = self.on_full_token.take() {
-            self.inner.slot.cancel(token);
+        let mut state = self.inner.state.lock().unwrap();
+        state.queue.clear();
+        for (_, task) in state.blocked_senders.drain(..) {
+            task.unpark();
         }
-        self.inner.slot.on_full(|slot| {
-            drop(slot.try_consume());
-        });
     }
 }
 
@@ -129,16 +314,124 @@ impl<T, E> Sender<T, E> {
         FutureSender {
             sender: Some(self),
             data: Some(t),
-            on_empty_token: None,
+            parked_id: None,
+        }
+    }
+
+    /// Attempts to enqueue `t` without parking, for use outside of a task
+    /// context. Returns `TrySendError::Full` if the channel has no spare
+    /// capacity right now, or `TrySendError::Disconnected` if no message
+    /// could ever be delivered.
+    pub fn try_send(&self, t: Result<T, E>) -> Result<(), TrySendError<T, E>> {
+        if self.inner.receiver_gone.load(Ordering::SeqCst) {
+            return Err(TrySendError::Disconnected(t));
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        if state.closed {
+            return Err(TrySendError::Disconnected(t));
+        }
+        if state.queue.len() < state.capacity {
+            state.queue.push_back(Message::Data(t));
+            if let Some(task) = state.receiver_task.take() {
+                task.unpark();
+            }
+            Ok(())
+        } else {
+            Err(TrySendError::Full(t))
+        }
+    }
+}
+
+/// The transmission end of an unbounded channel created by
+/// `create_unbounded`.
+///
+/// Unlike `Sender`, whose `send` returns a `FutureSender` that may need to
+/// park, this queue never fills up, so `send` resolves synchronously.
+pub struct UnboundedSender<T, E> {
+    inner: Sender<T, E>,
+}
+
+impl<T, E> UnboundedSender<T, E> {
+    pub fn send(&self, t: Result<T, E>) -> Result<(), SendError<T, E>> {
+        self.inner.try_send(t).map_err(|e| SendError(e.into_inner()))
+    }
+}
+
+impl<T, E> Clone for UnboundedSender<T, E> {
+    fn clone(&self) -> UnboundedSender<T, E> {
+        UnboundedSender {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T, E> Sink for Sender<T, E> {
+    type SinkItem = Result<T, E>;
+    type SinkError = SendError<T, E>;
+
+    /// Attempts to push `item` straight into the channel without the caller
+    /// having to drive a `FutureSender` to completion. Mirrors
+    /// `FutureSender::poll`: if there's room the item is queued immediately,
+    /// otherwise the current task is parked to be woken once a slot frees up
+    /// and `AsyncSink::NotReady` is returned with the item handed back.
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if let Some(id) = self.parked_id.take() {
+            let mut state = self.inner.state.lock().unwrap();
+            state.unregister_blocked_sender(id);
+        }
+        if self.inner.receiver_gone.load(Ordering::SeqCst) {
+            return Err(SendError(item));
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        if state.closed {
+            return Err(SendError(item));
+        }
+        if state.queue.len() < state.capacity {
+            state.queue.push_back(Message::Data(item));
+            if let Some(task) = state.receiver_task.take() {
+                task.unpark();
+            }
+            Ok(AsyncSink::Ready)
+        } else {
+            self.parked_id = Some(state.park_current_sender());
+            Ok(AsyncSink::NotReady(item))
+        }
+    }
+
+    /// Every accepted item is already visible to the receiver by the time
+    /// `start_send` returns, so there's nothing left to flush.
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<T, E> Clone for Sender<T, E> {
+    fn clone(&self) -> Sender<T, E> {
+        self.inner.sender_count.fetch_add(1, Ordering::SeqCst);
+        Sender {
+            inner: self.inner.clone(),
+            parked_id: None,
         }
     }
 }
 
 impl<T, E> Drop for Sender<T, E> {
     fn drop(&mut self) {
-        self.inner.slot.on_empty(None, |slot, _none| {
-            slot.try_produce(Message::Done).ok().unwrap();
-        });
+        let parked_id = self.parked_id.take();
+        let is_last = self.inner.sender_count.fetch_sub(1, Ordering::SeqCst) == 1;
+        if parked_id.is_none() && !is_last {
+            return;
+        }
+        let mut state = self.inner.state.lock().unwrap();
+        if let Some(id) = parked_id {
+            state.unregister_blocked_sender(id);
+        }
+        if is_last {
+            state.queue.push_back(Message::Done);
+            if let Some(task) = state.receiver_task.take() {
+                task.unpark();
+            }
+        }
     }
 }
 
@@ -149,37 +442,149 @@ impl<T, E> Future for FutureSender<T, E> {
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         let data = self.data.take().expect("cannot poll FutureSender twice");
         let sender = self.sender.take().expect("cannot poll FutureSender twice");
-        if let Some(token) = self.on_empty_token.take() {
-            sender.inner.slot.cancel(token);
+        if let Some(id) = self.parked_id.take() {
+            let mut state = sender.inner.state.lock().unwrap();
+            state.unregister_blocked_sender(id);
         }
         if sender.inner.receiver_gone.load(Ordering::SeqCst) {
             return Err(SendError(data))
         }
-        match sender.inner.slot.try_produce(Message::Data(data)) {
-            Ok(()) => Ok(Async::Ready(sender)),
-            Err(e) => {
-                let task = task::park();
-                let token = sender.inner.slot.on_empty(None, move |_slot, _item| {
-                    task.unpark();
-                });
-                self.on_empty_token = Some(token);
-                self.data = Some(match e.into_inner() {
-                    Message::Data(data) => data,
-                    Message::Done => panic!(),
-                });
-                self.sender = Some(sender);
-                Ok(Async::NotReady)
+        let mut state = sender.inner.state.lock().unwrap();
+        if state.closed {
+            return Err(SendError(data));
+        }
+        if state.queue.len() < state.capacity {
+            state.queue.push_back(Message::Data(data));
+            if let Some(task) = state.receiver_task.take() {
+                task.unpark();
             }
+            drop(state);
+            Ok(Async::Ready(sender))
+        } else {
+            self.parked_id = Some(state.park_current_sender());
+            drop(state);
+            self.data = Some(data);
+            self.sender = Some(sender);
+            Ok(Async::NotReady)
         }
     }
 }
 
 impl<T, E> Drop for FutureSender<T, E> {
     fn drop(&mut self) {
-        if let Some(token) = self.on_empty_token.take() {
+        if let Some(id) = self.parked_id.take() {
             if let Some(sender) = self.sender.take() {
-                sender.inner.slot.cancel(token);
+                let mut state = sender.inner.state.lock().unwrap();
+                state.unregister_blocked_sender(id);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_not_ready<T, E>(p: Poll<T, E>) {
+        match p {
+            Ok(Async::NotReady) => {}
+            Ok(Async::Ready(_)) => panic!("expected Ok(Async::NotReady), got Ok(Async::Ready(_))"),
+            Err(_) => panic!("expected Ok(Async::NotReady), got Err(_)"),
+        }
+    }
+
+    #[test]
+    fn mpsc_done_only_after_last_sender_clone_drops() {
+        let (tx, mut rx) = create_mpsc::<u32, ()>();
+        let tx2 = tx.clone();
+        drop(tx);
+        assert_not_ready(rx.poll());
+        drop(tx2);
+        match rx.poll() {
+            Ok(Async::Ready(None)) => {}
+            Ok(Async::Ready(Some(_))) => panic!("expected Ok(Async::Ready(None)), got more data"),
+            Ok(Async::NotReady) => panic!("expected Ok(Async::Ready(None)), got Ok(Async::NotReady)"),
+            Err(_) => panic!("expected Ok(Async::Ready(None)), got Err(_)"),
+        }
+    }
+
+    #[test]
+    fn bounded_wakes_blocked_senders_in_fifo_order() {
+        let (tx, rx) = create_bounded::<u32, ()>(1);
+        tx.try_send(Ok(1)).unwrap();
+        let mut first = tx.clone().send(Ok(2));
+        let mut second = tx.clone().send(Ok(3));
+        assert_not_ready(first.poll());
+        assert_not_ready(second.poll());
+        let ids: Vec<u64> = {
+            let state = rx.inner.state.lock().unwrap();
+            state.blocked_senders.iter().map(|&(id, _)| id).collect()
+        };
+        assert_eq!(ids.len(), 2);
+        assert!(ids[0] < ids[1], "the first sender to park should be woken first");
+    }
+
+    #[test]
+    fn dropping_a_parked_future_sender_retracts_its_registration() {
+        let (tx, rx) = create_bounded::<u32, ()>(1);
+        tx.try_send(Ok(1)).unwrap();
+        let mut blocked = tx.clone().send(Ok(2));
+        assert_not_ready(blocked.poll());
+        assert_eq!(rx.inner.state.lock().unwrap().blocked_senders.len(), 1);
+        drop(blocked);
+        assert_eq!(rx.inner.state.lock().unwrap().blocked_senders.len(), 0);
+    }
+
+    #[test]
+    fn close_drains_buffer_before_ready_none_and_unblocks_parked_send() {
+        let (tx, mut rx) = create_bounded::<u32, ()>(1);
+        tx.try_send(Ok(1)).unwrap();
+        let mut blocked = tx.clone().send(Ok(2));
+        assert_not_ready(blocked.poll());
+
+        rx.close();
+
+        // The parked send must fail instead of parking again and hanging forever.
+        match blocked.poll() {
+            Err(_) => {}
+            Ok(_) => panic!("expected the parked send to fail after close(), but it didn't"),
+        }
+
+        // Already-buffered data is still delivered before the stream ends.
+        match rx.poll() {
+            Ok(Async::Ready(Some(1))) => {}
+            other => panic!("expected Ok(Async::Ready(Some(1))), got a different result: {}", other.is_ok()),
+        }
+        match rx.poll() {
+            Ok(Async::Ready(None)) => {}
+            other => panic!("expected Ok(Async::Ready(None)), got a different result: {}", other.is_ok()),
+        }
+    }
+
+    #[test]
+    fn try_send_distinguishes_full_from_disconnected() {
+        let (tx, rx) = create_bounded::<u32, ()>(1);
+        tx.try_send(Ok(1)).unwrap();
+        match tx.try_send(Ok(2)) {
+            Err(ref e) if e.is_full() => {}
+            other => panic!("expected TrySendError::Full, got {:?}", other),
+        }
+
+        drop(rx);
+        match tx.try_send(Ok(3)) {
+            Err(ref e) if e.is_disconnected() => {}
+            other => panic!("expected TrySendError::Disconnected, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unbounded_send_never_blocks_and_errors_after_receiver_drop() {
+        let (tx, rx) = create_unbounded::<u32, ()>();
+        for i in 0..1024 {
+            tx.send(Ok(i)).unwrap();
+        }
+        drop(rx);
+        assert!(tx.send(Ok(1)).is_err());
+    }
+}
+